@@ -1,15 +1,70 @@
+use std::convert::Infallible;
 use std::future::Future;
 use std::net::SocketAddr;
 use std::pin::Pin;
+use std::sync::Arc;
 use std::task::Poll;
 
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 use hyper::HeaderMap;
 use hyper::{body::HttpBody, Body, Request, Response};
 use pin_project::pin_project;
 use tonic::async_trait;
 use tonic_example::echo_server::{Echo, EchoServer};
 use tonic_example::{EchoReply, EchoRequest};
-use tower::Service;
+use tower::{Service, ServiceExt};
+
+/// Completes the WebSocket handshake, then echoes frames back until the client closes.
+async fn echo_websocket(req: Request<Body>) -> Result<Response<Body>, std::convert::Infallible> {
+    let accept_key = req
+        .headers()
+        .get("sec-websocket-key")
+        .and_then(|v| v.to_str().ok())
+        .map(websocket_accept_key)
+        .unwrap_or_default();
+
+    tokio::spawn(async move {
+        match hyper::upgrade::on(req).await {
+            Ok(upgraded) => {
+                let mut ws = tokio_tungstenite::WebSocketStream::from_raw_socket(
+                    upgraded,
+                    tokio_tungstenite::tungstenite::protocol::Role::Server,
+                    None,
+                )
+                .await;
+
+                while let Some(Ok(msg)) = futures_util::StreamExt::next(&mut ws).await {
+                    if msg.is_close() {
+                        break;
+                    }
+                    if futures_util::SinkExt::send(&mut ws, msg).await.is_err() {
+                        break;
+                    }
+                }
+            }
+            Err(e) => eprintln!("websocket upgrade error: {}", e),
+        }
+    });
+
+    Ok(Response::builder()
+        .status(hyper::StatusCode::SWITCHING_PROTOCOLS)
+        .header(hyper::header::CONNECTION, "upgrade")
+        .header(hyper::header::UPGRADE, "websocket")
+        .header("sec-websocket-accept", accept_key)
+        .body(Body::empty())
+        .expect("building a static response cannot fail"))
+}
+
+/// Computes `Sec-WebSocket-Accept` per RFC 6455 §1.3: SHA-1 of the client's key concatenated
+/// with the WebSocket GUID, base64-encoded.
+fn websocket_accept_key(client_key: &str) -> String {
+    use sha1::Digest;
+
+    let mut hasher = sha1::Sha1::new();
+    hasher.update(client_key.as_bytes());
+    hasher.update(b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11");
+    base64::encode(hasher.finalize())
+}
 
 struct MyEcho;
 
@@ -19,8 +74,14 @@ impl Echo for MyEcho {
         &self,
         request: tonic::Request<EchoRequest>,
     ) -> Result<tonic::Response<EchoReply>, tonic::Status> {
+        let peer = request
+            .extensions()
+            .get::<ConnInfo>()
+            .map(|info| info.remote_addr.to_string())
+            .unwrap_or_else(|| "unknown".to_string());
+
         Ok(tonic::Response::new(EchoReply {
-            message: format!("Echoing back: {}", request.get_ref().message),
+            message: format!("Echoing back to {}: {}", peer, request.get_ref().message),
         }))
     }
 }
@@ -29,15 +90,43 @@ impl Echo for MyEcho {
 async fn main() {
     let addr = SocketAddr::from(([0, 0, 0, 0], 3000));
 
-    let axum_make_service = axum::Router::new()
-        .route("/", axum::handler::get(|| async { "Hello world!" }))
-        .into_make_service();
-
-    let grpc_service = tonic::transport::Server::builder()
-        .add_service(EchoServer::new(MyEcho))
-        .into_service();
-
-    let hybrid_make_service = hybrid(axum_make_service, grpc_service);
+    let web_service = WebRecover::new(
+        axum::Router::new().route("/", axum::handler::get(|| async { "Hello world!" })),
+    );
+
+    let grpc_service = GrpcRecover::new(
+        tonic::transport::Server::builder()
+            .add_service(EchoServer::new(MyEcho))
+            .into_service(),
+    );
+
+    let grpc_web_service = {
+        let grpc = grpc_service.clone();
+        tower::service_fn(move |req: Request<Body>| {
+            let grpc = grpc.clone();
+            async move {
+                let coding = grpc_web_coding(content_type(&req))
+                    .expect("route predicate only dispatches recognized grpc-web content types");
+                call_grpc_web(grpc, req, coding).await
+            }
+        })
+    };
+
+    let ws_service = UpgradeGuard::new(tower::service_fn(echo_websocket));
+
+    let router = HybridRouterBuilder::new()
+        .route(
+            |req| grpc_web_coding(content_type(req)).is_some(),
+            grpc_web_service,
+        )
+        .route(
+            |req| content_type(req).starts_with("application/grpc"),
+            grpc_service,
+        )
+        .route(is_websocket_upgrade, ws_service)
+        .fallback(web_service);
+
+    let hybrid_make_service = HybridMakeRouter::new(router);
 
     let server = hyper::Server::bind(&addr).serve(hybrid_make_service);
 
@@ -46,168 +135,473 @@ async fn main() {
     }
 }
 
-fn hybrid<MakeWeb, Grpc>(make_web: MakeWeb, grpc: Grpc) -> HybridMakeService<MakeWeb, Grpc> {
-    HybridMakeService { make_web, grpc }
+/// Extracts the `content-type` header as `&str`, or `""` if absent or not visible ASCII.
+fn content_type(req: &Request<Body>) -> &str {
+    req.headers()
+        .get("content-type")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default()
 }
 
-struct HybridMakeService<MakeWeb, Grpc> {
-    make_web: MakeWeb,
-    grpc: Grpc,
+/// Whether a request is an HTTP/1.1 WebSocket upgrade handshake.
+fn is_websocket_upgrade(req: &Request<Body>) -> bool {
+    let has_connection_upgrade = req
+        .headers()
+        .get(hyper::header::CONNECTION)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.split(',').any(|token| token.trim().eq_ignore_ascii_case("upgrade")))
+        .unwrap_or(false);
+
+    let has_upgrade_websocket = req
+        .headers()
+        .get(hyper::header::UPGRADE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.eq_ignore_ascii_case("websocket"))
+        .unwrap_or(false);
+
+    has_connection_upgrade
+        && has_upgrade_websocket
+        && req.headers().contains_key("sec-websocket-key")
+}
+
+/// Mirrors the validation performed by upgrade-verifier services: a WebSocket handshake must be
+/// a `GET` request on HTTP/1.1 or later.
+fn validate_upgrade_request(req: &Request<Body>) -> Result<(), Response<Body>> {
+    if req.method() != hyper::Method::GET || req.version() < hyper::Version::HTTP_11 {
+        return Err(Response::builder()
+            .status(hyper::StatusCode::BAD_REQUEST)
+            .body(Body::from("invalid websocket upgrade request"))
+            .expect("building a static response cannot fail"));
+    }
+
+    Ok(())
+}
+
+/// Wraps the WebSocket service so a malformed upgrade handshake (rejected by
+/// `validate_upgrade_request`) gets a static 400 instead of being handed to the backend.
+#[derive(Clone)]
+struct UpgradeGuard<S> {
+    inner: S,
+}
+
+impl<S> UpgradeGuard<S> {
+    fn new(inner: S) -> Self {
+        Self { inner }
+    }
 }
 
-impl<ConnInfo, MakeWeb, Grpc> Service<ConnInfo> for HybridMakeService<MakeWeb, Grpc>
+impl<S> Service<Request<Body>> for UpgradeGuard<S>
 where
-    MakeWeb: Service<ConnInfo>,
-    Grpc: Clone,
+    S: Service<Request<Body>, Response = Response<Body>, Error = Infallible>,
 {
-    type Response = HybridService<MakeWeb::Response, Grpc>;
-    type Error = MakeWeb::Error;
-    type Future = HybridMakeServiceFuture<MakeWeb::Future, Grpc>;
+    type Response = Response<Body>;
+    type Error = Infallible;
+    type Future = UpgradeGuardFuture<S::Future>;
 
-    fn poll_ready(
-        &mut self,
-        cx: &mut std::task::Context,
-    ) -> std::task::Poll<Result<(), Self::Error>> {
-        self.make_web.poll_ready(cx)
+    fn poll_ready(&mut self, cx: &mut std::task::Context) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
     }
 
-    fn call(&mut self, conn_info: ConnInfo) -> Self::Future {
-        HybridMakeServiceFuture {
-            web_future: self.make_web.call(conn_info),
-            grpc: Some(self.grpc.clone()),
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        match validate_upgrade_request(&req) {
+            Ok(()) => UpgradeGuardFuture::Inner(self.inner.call(req)),
+            Err(response) => UpgradeGuardFuture::BadRequest(Some(response)),
         }
     }
 }
 
-#[pin_project]
-struct HybridMakeServiceFuture<WebFuture, Grpc> {
-    #[pin]
-    web_future: WebFuture,
-    grpc: Option<Grpc>,
+#[pin_project(project = UpgradeGuardProj)]
+enum UpgradeGuardFuture<F> {
+    Inner(#[pin] F),
+    /// A rejected upgrade handshake; resolves immediately to a static 400 response.
+    BadRequest(Option<Response<Body>>),
 }
 
-impl<WebFuture, Web, WebError, Grpc> Future for HybridMakeServiceFuture<WebFuture, Grpc>
+impl<F> Future for UpgradeGuardFuture<F>
 where
-    WebFuture: Future<Output = Result<Web, WebError>>,
+    F: Future<Output = Result<Response<Body>, Infallible>>,
 {
-    type Output = Result<HybridService<Web, Grpc>, WebError>;
+    type Output = Result<Response<Body>, Infallible>;
 
     fn poll(self: Pin<&mut Self>, cx: &mut std::task::Context) -> Poll<Self::Output> {
-        let this = self.project();
-        match this.web_future.poll(cx) {
-            Poll::Pending => Poll::Pending,
-            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
-            Poll::Ready(Ok(web)) => Poll::Ready(Ok(HybridService {
-                web,
-                grpc: this.grpc.take().expect("Cannot poll twice!"),
-            })),
+        match self.project() {
+            UpgradeGuardProj::Inner(f) => f.poll(cx),
+            UpgradeGuardProj::BadRequest(res) => {
+                Poll::Ready(Ok(res.take().expect("Cannot poll twice!")))
+            }
         }
     }
 }
 
-struct HybridService<Web, Grpc> {
-    web: Web,
-    grpc: Grpc,
+/// How a grpc-web request/response body is framed on the wire.
+#[derive(Clone, Copy)]
+enum GrpcWebCoding {
+    /// `application/grpc-web`, `application/grpc-web+proto`: raw length-prefixed frames.
+    Binary,
+    /// `application/grpc-web-text`, `application/grpc-web-text+proto`: frames base64-encoded.
+    Text,
+}
+
+/// Returns the wire coding for a grpc-web `content-type`, or `None` if it isn't grpc-web at all.
+fn grpc_web_coding(content_type: &str) -> Option<GrpcWebCoding> {
+    let subtype = content_type.strip_prefix("application/grpc-web")?;
+    match subtype {
+        "-text" | "-text+proto" | "-text+json" => Some(GrpcWebCoding::Text),
+        "" | "+proto" | "+json" => Some(GrpcWebCoding::Binary),
+        _ => None,
+    }
 }
 
-impl<Web, Grpc, WebBody, GrpcBody> Service<Request<Body>> for HybridService<Web, Grpc>
+/// Translates a grpc-web request into a native gRPC request, drives it through `grpc`, and
+/// translates the response back into grpc-web framing.
+async fn call_grpc_web<Grpc, GrpcBody>(
+    mut grpc: Grpc,
+    req: Request<Body>,
+    coding: GrpcWebCoding,
+) -> Result<Response<GrpcWebBody<GrpcBody>>, Box<dyn std::error::Error + Send + Sync + 'static>>
 where
-    Web: Service<Request<Body>, Response = Response<WebBody>>,
     Grpc: Service<Request<Body>, Response = Response<GrpcBody>>,
-    Web::Error: Into<Box<dyn std::error::Error + Send + Sync + 'static>>,
     Grpc::Error: Into<Box<dyn std::error::Error + Send + Sync + 'static>>,
+    GrpcBody: HttpBody<Data = Bytes>,
+    GrpcBody::Error: std::error::Error,
 {
-    type Response = Response<HybridBody<WebBody, GrpcBody>>;
-    type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
-    type Future = HybridFuture<Web::Future, Grpc::Future>;
-
-    fn poll_ready(
-        &mut self,
-        cx: &mut std::task::Context<'_>,
-    ) -> std::task::Poll<Result<(), Self::Error>> {
-        match self.web.poll_ready(cx) {
-            Poll::Ready(Ok(())) => match self.grpc.poll_ready(cx) {
-                Poll::Ready(Ok(())) => Poll::Ready(Ok(())),
-                Poll::Ready(Err(e)) => Poll::Ready(Err(e.into())),
-                Poll::Pending => Poll::Pending,
-            },
-            Poll::Ready(Err(e)) => Poll::Ready(Err(e.into())),
+    let (mut parts, body) = req.into_parts();
+
+    let body_bytes = hyper::body::to_bytes(body)
+        .await
+        .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync + 'static>)?;
+
+    let body = match coding {
+        GrpcWebCoding::Binary => body_bytes,
+        GrpcWebCoding::Text => base64::decode(&body_bytes)
+            .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync + 'static>)?
+            .into(),
+    };
+
+    parts
+        .headers
+        .insert("content-type", hyper::header::HeaderValue::from_static("application/grpc"));
+
+    let grpc_req = Request::from_parts(parts, Body::from(body));
+
+    futures_util::future::poll_fn(|cx| grpc.poll_ready(cx))
+        .await
+        .map_err(Into::into)?;
+
+    let res = grpc.call(grpc_req).await.map_err(Into::into)?;
+    Ok(res.map(|b| GrpcWebBody::new(b, coding)))
+}
+
+/// Wraps a gRPC inner service so a handler error is recovered into a gRPC-compliant response
+/// instead of bubbling up as a boxed error and tearing down the connection.
+#[derive(Clone)]
+struct GrpcRecover<S> {
+    inner: S,
+    /// A readiness error observed by `poll_ready`, resolved into a recovered response by the
+    /// next `call` instead of forwarding to an inner service that never confirmed readiness.
+    failed: Option<tonic::Status>,
+}
+
+impl<S> GrpcRecover<S> {
+    fn new(inner: S) -> Self {
+        Self {
+            inner,
+            failed: None,
+        }
+    }
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for GrpcRecover<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    S::Error: Into<Box<dyn std::error::Error + Send + Sync + 'static>>,
+{
+    type Response = Response<GrpcRecoverBody<ResBody>>;
+    type Error = Infallible;
+    type Future = GrpcRecoverFuture<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut std::task::Context) -> Poll<Result<(), Self::Error>> {
+        // A readiness error would defeat the point of this layer; stash it and report
+        // ready anyway, so `call` can recover it without ever invoking the inner service.
+        match self.inner.poll_ready(cx) {
             Poll::Pending => Poll::Pending,
+            Poll::Ready(Ok(())) => {
+                self.failed = None;
+                Poll::Ready(Ok(()))
+            }
+            Poll::Ready(Err(e)) => {
+                self.failed = Some(status_from_error(e.into()));
+                Poll::Ready(Ok(()))
+            }
         }
     }
 
-    fn call(&mut self, req: Request<Body>) -> Self::Future {
-        if req.headers().get("content-type").map(|x| x.as_bytes()) == Some(b"application/grpc") {
-            HybridFuture::Right(self.grpc.call(req))
-        } else {
-            HybridFuture::Left(self.web.call(req))
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        match self.failed.take() {
+            Some(status) => GrpcRecoverFuture::Failed(Some(status)),
+            None => GrpcRecoverFuture::Inner(self.inner.call(req)),
         }
     }
 }
 
-enum HybridError<WebError, GrpcError> {
-    Web(WebError),
-    Grpc(GrpcError),
+#[pin_project(project = GrpcRecoverFutureProj)]
+enum GrpcRecoverFuture<F> {
+    Inner(#[pin] F),
+    /// A readiness error stashed by `poll_ready`; resolves to a recovered response without
+    /// ever calling the inner service.
+    Failed(Option<tonic::Status>),
 }
 
-impl<WebError, GrpcError> std::fmt::Display for HybridError<WebError, GrpcError>
+impl<F, ResBody, E> Future for GrpcRecoverFuture<F>
 where
-    WebError: std::fmt::Display,
-    GrpcError: std::fmt::Display,
+    F: Future<Output = Result<Response<ResBody>, E>>,
+    E: Into<Box<dyn std::error::Error + Send + Sync + 'static>>,
 {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Self::Web(a) => std::fmt::Display::fmt(a, f),
-            Self::Grpc(b) => std::fmt::Display::fmt(b, f),
+    type Output = Result<Response<GrpcRecoverBody<ResBody>>, Infallible>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut std::task::Context) -> Poll<Self::Output> {
+        match self.project() {
+            GrpcRecoverFutureProj::Inner(f) => match f.poll(cx) {
+                Poll::Pending => Poll::Pending,
+                Poll::Ready(Ok(res)) => Poll::Ready(Ok(res.map(GrpcRecoverBody::Inner))),
+                Poll::Ready(Err(e)) => {
+                    Poll::Ready(Ok(grpc_status_response(status_from_error(e.into()))))
+                }
+            },
+            GrpcRecoverFutureProj::Failed(status) => Poll::Ready(Ok(grpc_status_response(
+                status.take().expect("GrpcRecoverFuture::Failed polled twice"),
+            ))),
         }
     }
 }
 
-impl<WebError, GrpcError> std::fmt::Debug for HybridError<WebError, GrpcError>
+/// Maps a boxed handler error to a `tonic::Status`, defaulting to code 13 (`Internal`)
+/// when the error isn't already one.
+fn status_from_error(err: Box<dyn std::error::Error + Send + Sync + 'static>) -> tonic::Status {
+    match err.downcast::<tonic::Status>() {
+        Ok(status) => *status,
+        Err(err) => tonic::Status::new(tonic::Code::Internal, err.to_string()),
+    }
+}
+
+/// Builds the gRPC response for a recovered handler error: an empty body carrying
+/// `grpc-status`/`grpc-message` as trailers.
+fn grpc_status_response<B>(status: tonic::Status) -> Response<GrpcRecoverBody<B>> {
+    Response::builder()
+        .status(hyper::StatusCode::OK)
+        .header(hyper::header::CONTENT_TYPE, "application/grpc")
+        .body(GrpcRecoverBody::Status(GrpcStatusBody::new(status)))
+        .expect("building a static response cannot fail")
+}
+
+enum GrpcRecoverBody<B> {
+    Inner(B),
+    /// A recovered handler error, carried entirely as trailers (no data frames).
+    Status(GrpcStatusBody),
+}
+
+impl<B> HttpBody for GrpcRecoverBody<B>
 where
-    WebError: std::fmt::Debug,
-    GrpcError: std::fmt::Debug,
+    B: HttpBody<Data = Bytes>,
+    B::Error: std::error::Error + Send + Sync + 'static,
 {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    type Data = Bytes;
+    type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+    fn is_end_stream(&self) -> bool {
         match self {
-            Self::Web(a) => std::fmt::Debug::fmt(a, f),
-            Self::Grpc(b) => std::fmt::Debug::fmt(b, f),
+            Self::Inner(b) => b.is_end_stream(),
+            Self::Status(b) => b.is_end_stream(),
         }
     }
+
+    fn poll_data(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context,
+    ) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        match self.get_mut() {
+            Self::Inner(b) => Pin::new(b).poll_data(cx).map_err(|e| Box::new(e) as _),
+            Self::Status(b) => Pin::new(b).poll_data(cx).map_err(|e| match e {}),
+        }
+    }
+
+    fn poll_trailers(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        match self.get_mut() {
+            Self::Inner(b) => Pin::new(b).poll_trailers(cx).map_err(|e| Box::new(e) as _),
+            Self::Status(b) => Pin::new(b).poll_trailers(cx).map_err(|e| match e {}),
+        }
+    }
+}
+
+/// The body of a recovered gRPC error response: no data frames, just status trailers.
+struct GrpcStatusBody {
+    trailers: Option<HeaderMap>,
+}
+
+impl GrpcStatusBody {
+    fn new(status: tonic::Status) -> Self {
+        Self {
+            trailers: Some(status_trailers(status)),
+        }
+    }
+}
+
+/// Encodes a `tonic::Status` as `grpc-status`/`grpc-message` trailers. The message is sent
+/// as-is, not percent-encoded; `grpc-message` is left out when the message is empty or is not
+/// a valid header value.
+fn status_trailers(status: tonic::Status) -> HeaderMap {
+    let mut trailers = HeaderMap::new();
+    trailers.insert(
+        "grpc-status",
+        hyper::header::HeaderValue::from_str(&(status.code() as i32).to_string())
+            .expect("a status code formats to a valid header value"),
+    );
+    if !status.message().is_empty() {
+        if let Ok(value) = hyper::header::HeaderValue::from_str(status.message()) {
+            trailers.insert("grpc-message", value);
+        }
+    }
+    trailers
+}
+
+impl HttpBody for GrpcStatusBody {
+    type Data = Bytes;
+    type Error = Infallible;
+
+    fn is_end_stream(&self) -> bool {
+        self.trailers.is_none()
+    }
+
+    fn poll_data(
+        self: Pin<&mut Self>,
+        _cx: &mut std::task::Context,
+    ) -> Poll<Option<Result<Bytes, Infallible>>> {
+        Poll::Ready(None)
+    }
+
+    fn poll_trailers(
+        self: Pin<&mut Self>,
+        _cx: &mut std::task::Context,
+    ) -> Poll<Result<Option<HeaderMap>, Infallible>> {
+        Poll::Ready(Ok(self.get_mut().trailers.take()))
+    }
 }
 
-impl<WebError: std::error::Error, GrpcError: std::error::Error> std::error::Error
-    for HybridError<WebError, GrpcError>
+/// Wraps the web inner service so a handler error is recovered into a plain 500 response
+/// instead of bubbling up as a boxed error, the symmetric treatment to `GrpcRecover`.
+#[derive(Clone)]
+struct WebRecover<S> {
+    inner: S,
+    /// A readiness error observed by `poll_ready`, resolved into a recovered response by the
+    /// next `call` instead of forwarding to an inner service that never confirmed readiness.
+    failed: Option<String>,
+}
+
+impl<S> WebRecover<S> {
+    fn new(inner: S) -> Self {
+        Self {
+            inner,
+            failed: None,
+        }
+    }
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for WebRecover<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    S::Error: Into<Box<dyn std::error::Error + Send + Sync + 'static>>,
 {
+    type Response = Response<WebRecoverBody<ResBody>>;
+    type Error = Infallible;
+    type Future = WebRecoverFuture<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut std::task::Context) -> Poll<Result<(), Self::Error>> {
+        match self.inner.poll_ready(cx) {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(Ok(())) => {
+                self.failed = None;
+                Poll::Ready(Ok(()))
+            }
+            Poll::Ready(Err(e)) => {
+                self.failed = Some(e.into().to_string());
+                Poll::Ready(Ok(()))
+            }
+        }
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        match self.failed.take() {
+            Some(message) => WebRecoverFuture::Failed(Some(message)),
+            None => WebRecoverFuture::Inner(self.inner.call(req)),
+        }
+    }
 }
 
-enum HybridBody<WebBody, GrpcBody> {
-    Web(WebBody),
-    Grpc(GrpcBody),
+#[pin_project(project = WebRecoverFutureProj)]
+enum WebRecoverFuture<F> {
+    Inner(#[pin] F),
+    /// A readiness error stashed by `poll_ready`; resolves to a recovered response without
+    /// ever calling the inner service.
+    Failed(Option<String>),
 }
 
-impl<WebBody, GrpcBody> HttpBody for HybridBody<WebBody, GrpcBody>
+impl<F, ResBody, E> Future for WebRecoverFuture<F>
 where
-    WebBody: HttpBody + Send + Unpin,
-    GrpcBody: HttpBody<Data = WebBody::Data> + Send + Unpin,
+    F: Future<Output = Result<Response<ResBody>, E>>,
+    E: Into<Box<dyn std::error::Error + Send + Sync + 'static>>,
 {
-    type Data = WebBody::Data;
-    type Error = HybridError<WebBody::Error, GrpcBody::Error>;
+    type Output = Result<Response<WebRecoverBody<ResBody>>, Infallible>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut std::task::Context) -> Poll<Self::Output> {
+        let message = match self.project() {
+            WebRecoverFutureProj::Inner(f) => match f.poll(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Ok(res)) => return Poll::Ready(Ok(res.map(WebRecoverBody::Inner))),
+                Poll::Ready(Err(e)) => e.into().to_string(),
+            },
+            WebRecoverFutureProj::Failed(message) => {
+                message.take().expect("WebRecoverFuture::Failed polled twice")
+            }
+        };
+
+        Poll::Ready(Ok(Response::builder()
+            .status(hyper::StatusCode::INTERNAL_SERVER_ERROR)
+            .body(WebRecoverBody::Error(Body::from(message)))
+            .expect("building a static response cannot fail")))
+    }
+}
+
+enum WebRecoverBody<B> {
+    Inner(B),
+    /// A recovered handler error, rendered as a static 500 response body.
+    Error(Body),
+}
+
+impl<B> HttpBody for WebRecoverBody<B>
+where
+    B: HttpBody<Data = Bytes>,
+    B::Error: std::error::Error + Send + Sync + 'static,
+{
+    type Data = Bytes;
+    type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
 
     fn is_end_stream(&self) -> bool {
         match self {
-            HybridBody::Web(b) => b.is_end_stream(),
-            HybridBody::Grpc(b) => b.is_end_stream(),
+            Self::Inner(b) => b.is_end_stream(),
+            Self::Error(b) => b.is_end_stream(),
         }
     }
 
     fn poll_data(
         self: Pin<&mut Self>,
         cx: &mut std::task::Context,
-    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+    ) -> Poll<Option<Result<Bytes, Self::Error>>> {
         match self.get_mut() {
-            HybridBody::Web(b) => Pin::new(b).poll_data(cx).map_err(HybridError::Web),
-            HybridBody::Grpc(b) => Pin::new(b).poll_data(cx).map_err(HybridError::Grpc),
+            Self::Inner(b) => Pin::new(b).poll_data(cx).map_err(|e| Box::new(e) as _),
+            Self::Error(b) => Pin::new(b).poll_data(cx).map_err(|e| Box::new(e) as _),
         }
     }
 
@@ -216,43 +610,391 @@ where
         cx: &mut std::task::Context,
     ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
         match self.get_mut() {
-            HybridBody::Web(b) => Pin::new(b).poll_trailers(cx).map_err(HybridError::Web),
-            HybridBody::Grpc(b) => Pin::new(b).poll_trailers(cx).map_err(HybridError::Grpc),
+            Self::Inner(b) => Pin::new(b).poll_trailers(cx).map_err(|e| Box::new(e) as _),
+            Self::Error(b) => Pin::new(b).poll_trailers(cx).map_err(|e| Box::new(e) as _),
         }
     }
 }
 
-#[pin_project(project = HybridFutureProj)]
-enum HybridFuture<WebFuture, GrpcFuture> {
-    Left(#[pin] WebFuture),
-    Right(#[pin] GrpcFuture),
+/// Wraps a gRPC response body and re-frames it for grpc-web consumption: once the inner body
+/// is exhausted, its trailers are folded into a trailing frame (MSB set on the type byte),
+/// base64-encoded as a whole when `coding` is `Text`.
+#[pin_project]
+struct GrpcWebBody<B> {
+    #[pin]
+    inner: B,
+    coding: GrpcWebCoding,
+    state: GrpcWebBodyState,
+    /// Bytes left over from the last 3-byte base64 group, carried into the next frame so the
+    /// response is one continuous base64 encoding rather than independently-padded chunks.
+    carry: BytesMut,
+}
+
+enum GrpcWebBodyState {
+    Streaming,
+    Done,
 }
 
-impl<WebFuture, GrpcFuture, WebBody, GrpcBody, WebError, GrpcError> Future
-    for HybridFuture<WebFuture, GrpcFuture>
+impl<B> GrpcWebBody<B> {
+    fn new(inner: B, coding: GrpcWebCoding) -> Self {
+        Self {
+            inner,
+            coding,
+            state: GrpcWebBodyState::Streaming,
+            carry: BytesMut::new(),
+        }
+    }
+}
+
+fn encode_trailer_frame(trailers: &HeaderMap) -> Bytes {
+    let mut text = String::new();
+    for (name, value) in trailers {
+        text.push_str(name.as_str());
+        text.push_str(": ");
+        text.push_str(value.to_str().unwrap_or_default());
+        text.push_str("\r\n");
+    }
+
+    let mut frame = BytesMut::with_capacity(5 + text.len());
+    frame.put_u8(0x80);
+    frame.put_u32(text.len() as u32);
+    frame.put_slice(text.as_bytes());
+    frame.freeze()
+}
+
+impl<B> HttpBody for GrpcWebBody<B>
 where
-    WebFuture: Future<Output = Result<Response<WebBody>, WebError>>,
-    GrpcFuture: Future<Output = Result<Response<GrpcBody>, GrpcError>>,
-    WebError: Into<Box<dyn std::error::Error + Send + Sync + 'static>>,
-    GrpcError: Into<Box<dyn std::error::Error + Send + Sync + 'static>>,
+    B: HttpBody<Data = Bytes>,
+    B::Error: std::error::Error,
 {
-    type Output = Result<
-        Response<HybridBody<WebBody, GrpcBody>>,
-        Box<dyn std::error::Error + Send + Sync + 'static>,
-    >;
+    type Data = Bytes;
+    type Error = B::Error;
 
-    fn poll(self: Pin<&mut Self>, cx: &mut std::task::Context) -> Poll<Self::Output> {
-        match self.project() {
-            HybridFutureProj::Left(a) => match a.poll(cx) {
-                Poll::Ready(Ok(res)) => Poll::Ready(Ok(res.map(HybridBody::Web))),
-                Poll::Ready(Err(e)) => Poll::Ready(Err(e.into())),
-                Poll::Pending => Poll::Pending,
-            },
-            HybridFutureProj::Right(b) => match b.poll(cx) {
-                Poll::Ready(Ok(res)) => Poll::Ready(Ok(res.map(HybridBody::Grpc))),
-                Poll::Ready(Err(e)) => Poll::Ready(Err(e.into())),
-                Poll::Pending => Poll::Pending,
-            },
+    fn is_end_stream(&self) -> bool {
+        matches!(self.state, GrpcWebBodyState::Done) && self.inner.is_end_stream()
+    }
+
+    fn poll_data(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        let mut this = self.project();
+
+        if let GrpcWebBodyState::Done = this.state {
+            return Poll::Ready(None);
+        }
+
+        match this.inner.as_mut().poll_data(cx) {
+            Poll::Ready(Some(Ok(data))) => {
+                let out = match this.coding {
+                    GrpcWebCoding::Binary => data,
+                    GrpcWebCoding::Text => encode_base64_chunk(this.carry, data),
+                };
+                Poll::Ready(Some(Ok(out)))
+            }
+            Poll::Ready(Some(Err(e))) => Poll::Ready(Some(Err(e))),
+            Poll::Ready(None) => {
+                // Data is exhausted; fold the trailers into a trailing data frame since
+                // grpc-web clients can't read real HTTP trailers.
+                match this.inner.as_mut().poll_trailers(cx) {
+                    Poll::Ready(Ok(trailers)) => {
+                        *this.state = GrpcWebBodyState::Done;
+                        let frame = encode_trailer_frame(&trailers.unwrap_or_default());
+                        let out = match this.coding {
+                            GrpcWebCoding::Binary => frame,
+                            GrpcWebCoding::Text => {
+                                this.carry.extend_from_slice(&frame);
+                                encode_base64_final(this.carry)
+                            }
+                        };
+                        Poll::Ready(Some(Ok(out)))
+                    }
+                    Poll::Ready(Err(e)) => Poll::Ready(Some(Err(e))),
+                    Poll::Pending => Poll::Pending,
+                }
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn poll_trailers(
+        self: Pin<&mut Self>,
+        _cx: &mut std::task::Context,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        // Trailers were already folded into the body by `poll_data`; grpc-web carries no
+        // real HTTP trailers.
+        Poll::Ready(Ok(None))
+    }
+}
+
+/// Appends `data` to `carry` and base64-encodes whole 3-byte groups, stashing the 0-2
+/// leftover bytes back into `carry` so padding never lands mid-stream.
+fn encode_base64_chunk(carry: &mut BytesMut, data: Bytes) -> Bytes {
+    carry.extend_from_slice(&data);
+    let whole_len = carry.len() - carry.len() % 3;
+    let encoded = base64::encode(&carry[..whole_len]);
+    carry.advance(whole_len);
+    Bytes::from(encoded)
+}
+
+/// Base64-encodes and clears whatever bytes are left in `carry`, terminating the stream.
+fn encode_base64_final(carry: &mut BytesMut) -> Bytes {
+    let encoded = base64::encode(&carry[..]);
+    carry.clear();
+    Bytes::from(encoded)
+}
+
+type BoxError = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+/// A type-erased response body, so `HybridRouter` can hold heterogeneous backends without a
+/// type parameter per route.
+struct BoxBody(Pin<Box<dyn HttpBody<Data = Bytes, Error = BoxError> + Send>>);
+
+impl BoxBody {
+    fn new<B>(body: B) -> Self
+    where
+        B: HttpBody<Data = Bytes> + Send + 'static,
+        B::Error: Into<BoxError>,
+    {
+        Self(Box::pin(MapErrBody { inner: body }))
+    }
+}
+
+impl HttpBody for BoxBody {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn is_end_stream(&self) -> bool {
+        self.0.is_end_stream()
+    }
+
+    fn poll_data(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context,
+    ) -> Poll<Option<Result<Bytes, BoxError>>> {
+        self.get_mut().0.as_mut().poll_data(cx)
+    }
+
+    fn poll_trailers(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context,
+    ) -> Poll<Result<Option<HeaderMap>, BoxError>> {
+        self.get_mut().0.as_mut().poll_trailers(cx)
+    }
+}
+
+#[pin_project]
+struct MapErrBody<B> {
+    #[pin]
+    inner: B,
+}
+
+impl<B> HttpBody for MapErrBody<B>
+where
+    B: HttpBody<Data = Bytes>,
+    B::Error: Into<BoxError>,
+{
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn poll_data(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context,
+    ) -> Poll<Option<Result<Bytes, BoxError>>> {
+        self.project().inner.poll_data(cx).map_err(Into::into)
+    }
+
+    fn poll_trailers(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context,
+    ) -> Poll<Result<Option<HeaderMap>, BoxError>> {
+        self.project().inner.poll_trailers(cx).map_err(Into::into)
+    }
+}
+
+type BoxFuture = Pin<Box<dyn Future<Output = Result<Response<BoxBody>, BoxError>> + Send>>;
+type BoxedService = tower::util::BoxCloneService<Request<Body>, Response<BoxBody>, BoxError>;
+type Predicate = Arc<dyn Fn(&Request<Body>) -> bool + Send + Sync>;
+
+/// Type-erases `service` into a [`BoxedService`] so it can sit in a [`HybridRouter`] alongside
+/// services of unrelated concrete types.
+fn box_route<S, B>(service: S) -> BoxedService
+where
+    S: Service<Request<Body>, Response = Response<B>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    S::Error: Into<BoxError>,
+    B: HttpBody<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+{
+    service
+        .map_response(|res: Response<B>| res.map(BoxBody::new))
+        .map_err(Into::into)
+        .boxed_clone()
+}
+
+#[derive(Clone)]
+struct Route {
+    predicate: Predicate,
+    service: BoxedService,
+}
+
+/// Builds an ordered N-way [`HybridRouter`]: routes are tried in the order added, falling
+/// back to the service passed to `fallback` if none match.
+struct HybridRouterBuilder {
+    routes: Vec<Route>,
+}
+
+impl HybridRouterBuilder {
+    fn new() -> Self {
+        Self { routes: Vec::new() }
+    }
+
+    fn route<S, B>(
+        mut self,
+        predicate: impl Fn(&Request<Body>) -> bool + Send + Sync + 'static,
+        service: S,
+    ) -> Self
+    where
+        S: Service<Request<Body>, Response = Response<B>> + Clone + Send + 'static,
+        S::Future: Send + 'static,
+        S::Error: Into<BoxError>,
+        B: HttpBody<Data = Bytes> + Send + 'static,
+        B::Error: Into<BoxError>,
+    {
+        self.routes.push(Route {
+            predicate: Arc::new(predicate),
+            service: box_route(service),
+        });
+        self
+    }
+
+    fn fallback<S, B>(self, service: S) -> HybridRouter
+    where
+        S: Service<Request<Body>, Response = Response<B>> + Clone + Send + 'static,
+        S::Future: Send + 'static,
+        S::Error: Into<BoxError>,
+        B: HttpBody<Data = Bytes> + Send + 'static,
+        B::Error: Into<BoxError>,
+    {
+        HybridRouter {
+            routes: self.routes,
+            fallback: box_route(service),
+        }
+    }
+}
+
+/// Dispatches each request to the first route whose predicate matches, in the order the
+/// routes were added, falling back to `fallback` when none claim it.
+#[derive(Clone)]
+struct HybridRouter {
+    routes: Vec<Route>,
+    fallback: BoxedService,
+}
+
+impl Service<Request<Body>> for HybridRouter {
+    type Response = Response<BoxBody>;
+    // Stays `BoxError`, not `Infallible`: `BoxedService` type-erases every route's error
+    // regardless of what each recovery layer narrowed it to.
+    type Error = BoxError;
+    type Future = BoxFuture;
+
+    fn poll_ready(&mut self, cx: &mut std::task::Context) -> Poll<Result<(), Self::Error>> {
+        for route in &mut self.routes {
+            match route.service.poll_ready(cx) {
+                Poll::Ready(Ok(())) => {}
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        self.fallback.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        match self.routes.iter_mut().find(|route| (route.predicate)(&req)) {
+            Some(route) => route.service.call(req),
+            None => self.fallback.call(req),
         }
     }
-}
\ No newline at end of file
+}
+
+/// Per-connection metadata stamped onto every request, recoverable via
+/// `request.extensions().get::<ConnInfo>()`.
+#[derive(Clone, Copy, Debug)]
+struct ConnInfo {
+    remote_addr: SocketAddr,
+}
+
+/// Implemented by whatever hyper hands a `MakeService` per connection (e.g. `&AddrStream`).
+trait RemoteAddr {
+    fn remote_addr(&self) -> SocketAddr;
+}
+
+impl RemoteAddr for hyper::server::conn::AddrStream {
+    fn remote_addr(&self) -> SocketAddr {
+        hyper::server::conn::AddrStream::remote_addr(self)
+    }
+}
+
+/// Adapts a `Clone` [`HybridRouter`] into the per-connection `MakeService` hyper's
+/// `Server::serve` expects, wrapping each clone in [`WithConnInfo`].
+struct HybridMakeRouter<S> {
+    inner: S,
+}
+
+impl<S> HybridMakeRouter<S> {
+    fn new(inner: S) -> Self {
+        Self { inner }
+    }
+}
+
+impl<'a, T, S> Service<&'a T> for HybridMakeRouter<S>
+where
+    S: Clone,
+    T: RemoteAddr,
+{
+    type Response = WithConnInfo<S>;
+    type Error = Infallible;
+    type Future = std::future::Ready<Result<Self::Response, Infallible>>;
+
+    fn poll_ready(&mut self, _cx: &mut std::task::Context) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, target: &'a T) -> Self::Future {
+        std::future::ready(Ok(WithConnInfo {
+            inner: self.inner.clone(),
+            conn_info: ConnInfo {
+                remote_addr: target.remote_addr(),
+            },
+        }))
+    }
+}
+
+/// Stamps this connection's [`ConnInfo`] onto every request before handing it to `inner`.
+#[derive(Clone)]
+struct WithConnInfo<S> {
+    inner: S,
+    conn_info: ConnInfo,
+}
+
+impl<S> Service<Request<Body>> for WithConnInfo<S>
+where
+    S: Service<Request<Body>>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut std::task::Context) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<Body>) -> Self::Future {
+        req.extensions_mut().insert(self.conn_info);
+        self.inner.call(req)
+    }
+}